@@ -1,74 +1,268 @@
-use crate::detail::{align_down, mut_offset};
+// To understand the AArch64 ABI facts used in this file, keep in mind:
+// * AAPCS64 callee-saved registers are x19--x28, the frame pointer x29 and
+//   the link register x30, plus the stack pointer itself. Everything else
+//   (x0--x18, x30 as a scratch value, the vector registers other than the
+//   low 64 bits of v8--v15) is caller-saved and does not need to survive
+//   a `swap`.
+// * AAPCS64 requires the stack pointer to be 16-byte aligned at any public
+//   interface (in particular, at every `bl`/`blr` and at function entry).
+// * The first two integer arguments are passed in x0 and x1, and are also
+//   the registers we use to carry the `swap` argument and the peer stack
+//   pointer across a context switch; this is an arbitrary choice, made to
+//   mirror the x86_64 backend's use of %rdi/%rsi for the same purpose.
+// * A generator's stack carries two extra words at its very top: the
+//   "anchor" (8 bytes below the top) -- a fixed pointer back into
+//   `trampoline` itself, used only so an unwinder's CFA-8 lookup lands on
+//   code whose CFI matches ours -- and the "link slot" (16 bytes below
+//   the top), which `swap_link` refreshes on every resume with the
+//   address of the parent's saved frame. The bottom-of-stack trampoline's
+//   x29 points at the link slot rather than at a fixed frame, so the
+//   frame-pointer chain always leads to whichever context most recently
+//   resumed the generator -- mirroring the CFA slot on the x86_64 backend.
+//   Both words must stay within the stack's allocated bytes: x29+8 (the
+//   anchor) and x29+16 (the CFA, one word past the anchor) are exactly
+//   `stack.end() - 8` and `stack.end()`, so the link slot can never sit
+//   any closer to the top than 16 bytes below it. In particular, writing
+//   it at an offset of 0 from `stack.end()` -- as an earlier revision of
+//   `swap_link` briefly did, before the anchor word existed to reserve
+//   room for it -- is an out-of-bounds write one word past the buffer.
+// * AAPCS64 also requires the low 64 bits of v8--v15 to be preserved
+//   across calls, so `swap`/`swap_link` save and restore them on the
+//   stack just like x29/x30, rather than relying on the compiler (there
+//   is no "call" here for it to hang that guarantee off of).
+use std::mem;
 use crate::reg_context::InitFn;
-use crate::stack::Stack;
+use crate::stack::{GeneratorStack, StackPointer};
 
-#[link(name = "asm", kind = "static")]
-extern "C" {
-    pub fn bootstrap_green_task();
-    pub fn prefetch(data: *const usize);
-    pub fn swap_registers(out_regs: *mut Registers, in_regs: *const Registers);
+/// prefetch data
+#[inline(always)]
+unsafe fn prefetch(data: *const usize) {
+    asm!(
+    "prfm pldl1keep, [$0]"
+    : // no output
+    : "r"(data)
+    :
+    : "volatile")
 }
 
-#[repr(C, align(16))]
+// Entry trampoline planted at the bottom of every generator stack. It is
+// only ever reached once, via the first `swap_link` into a freshly
+// initialized context, and its job is to call `fptr` with a frame whose
+// x29 chains back to whatever context most recently resumed us.
+//
+// x29 on entry does not point at a real caller frame the way an ordinary
+// call would leave it -- it points at this stack's link slot (see
+// `initialize_call_frame`), which `swap_link` overwrites with the live
+// parent frame address on every resume. That indirection is what lets a
+// frame-pointer walk (gdb, perf, dtrace) follow the chain out of a
+// generator into the context that is currently resuming it, rather than
+// whichever one happened to create it.
+//
+// CFA-8 (x29+8) is where an unwinder expects a return address -- a code
+// pointer with its own matching CFI. It can't be `fptr`: that's an
+// arbitrary function evaluated at its own entry under its own unrelated
+// CFI. So x29+8 holds the "anchor", a fixed pointer back into this same
+// trampoline; unwinding through it reapplies this same `x29+16` rule to
+// whatever the link slot currently holds, continuing into the live
+// parent frame instead of misreading `fptr`.
+#[naked]
+unsafe extern "C" fn trampoline() -> ! {
+    asm!(r#"
+    .cfi_startproc
+    .cfi_def_cfa x29, 16
+    .cfi_offset x30, -8
+    .cfi_offset x29, -16
+
+    ldr     x2, [sp]
+    blr     x2
+
+    mov     sp, x1
+    mov     x1, xzr
+    ldp     d8,  d9,  [sp], #16
+    ldp     d10, d11, [sp], #16
+    ldp     d12, d13, [sp], #16
+    ldp     d14, d15, [sp], #16
+    ldp     x29, x30, [sp], #16
+    br      lr
+    .cfi_endproc
+    "#)
+}
+
+unsafe fn initialize_call_frame<S: GeneratorStack>(regs: &mut Registers, fptr: InitFn, stack: &S) {
+    // Lay out, from the top of the stack down:
+    //   [anchor]         CFA-8: a fixed pointer back into `trampoline`,
+    //                    never touched again after this
+    //   [link slot]      CFA-16: refreshed by swap_link's `str` on every
+    //                    resume
+    //   [padding]        keeps the whole frame a multiple of 16 bytes, so
+    //                    the saved sp and the sp at `blr x2` are both
+    //                    16-byte aligned
+    //   [fptr]           read by the trampoline via `ldr x2, [sp]`; sp
+    //                    stays on this word across `blr x2`, so fptr's
+    //                    own frame lies below everything listed above
+    //   [trampoline]     x30, loaded by the first swap_link's `ldp`
+    //   [&link slot]     x29, ditto -- a fixed pointer, not a fixed value
+    //   [d8..d15]        matched by the `ldp` sequence in swap/swap_link;
+    //                    contents are irrelevant since a fresh generator
+    //                    has no float/SIMD state yet
+    let mut sp = StackPointer::new(stack.end());
+
+    sp.push(trampoline as usize); // anchor, CFA-8
+
+    sp.push(0usize); // link slot, placeholder until the first swap_link
+    let link_slot = sp.offset(0) as usize;
+
+    sp.push(0usize); // padding
+    sp.push(fptr as usize);
+    sp.push(trampoline as usize); // x30: entry point
+    sp.push(link_slot); // x29: address of the (refreshed) link slot
+
+    for _ in 0..8 {
+        sp.push(0usize);
+    }
+
+    regs.sp = sp.offset(0) as usize;
+}
+
+#[inline(always)]
+pub unsafe fn swap_link(
+    arg: usize,
+    new_sp: StackPointer,
+    new_stack_base: *mut usize,
+) -> (usize, StackPointer) {
+    let ret: usize;
+    let ret_sp: usize;
+    asm!(
+    r#"
+    # Set up the return address for the far branch back into this context.
+    adr     lr, 0f
+
+    # Save the frame pointer, link register, and callee-saved NEON
+    # registers of the old context.
+    stp     x29, x30, [sp, #-16]!
+    stp     d14, d15, [sp, #-16]!
+    stp     d12, d13, [sp, #-16]!
+    stp     d10, d11, [sp, #-16]!
+    stp     d8,  d9,  [sp, #-16]!
+
+    # Link the call stacks together by writing the address of the saved
+    # x29/x30 pair (64 bytes above the current stack bottom, past the NEON
+    # saves) to the link slot in the new stack (16 bytes below its top,
+    # see initialize_call_frame).
+    mov     x1, sp
+    add     x4, sp, #64
+    str     x4, [x3, #-16]
+
+    # Load stack pointer of the new context.
+    mov     sp, x2
+
+    # Restore the new context's callee-saved NEON registers, frame
+    # pointer, and link register.
+    ldp     d8,  d9,  [sp], #16
+    ldp     d10, d11, [sp], #16
+    ldp     d12, d13, [sp], #16
+    ldp     d14, d15, [sp], #16
+    ldp     x29, x30, [sp], #16
+
+    # Return into the new context.
+    br      lr
+    0:
+    "#
+    : "={x0}" (ret)
+      "={x1}" (ret_sp)
+    : "{x0}" (arg)
+      "{x2}" (new_sp.offset(0))
+      "{x3}" (new_stack_base)
+    : "x2",  "x3",  "x4",  "x5",  "x6",  "x7",  "x8",  "x9",
+      "x10", "x11", "x12", "x13", "x14", "x15", "x16", "x17",
+      "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+      "x27", "x28", "x30",
+      "v0",  "v1",  "v2",  "v3",  "v4",  "v5",  "v6",  "v7",
+      "v16", "v17", "v18", "v19", "v20", "v21", "v22", "v23",
+      "v24", "v25", "v26", "v27", "v28", "v29", "v30", "v31",
+      "cc", "memory"
+    : "volatile");
+    (ret, mem::transmute(ret_sp))
+}
+
+#[inline(always)]
+pub unsafe fn swap(arg: usize, new_sp: StackPointer) -> (usize, StackPointer) {
+    // This is identical to swap_link, but without the write to the link slot.
+    let ret: usize;
+    let ret_sp: usize;
+    asm!(
+    r#"
+    adr     lr, 0f
+    stp     x29, x30, [sp, #-16]!
+    stp     d14, d15, [sp, #-16]!
+    stp     d12, d13, [sp, #-16]!
+    stp     d10, d11, [sp, #-16]!
+    stp     d8,  d9,  [sp, #-16]!
+    mov     x1, sp
+    mov     sp, x2
+    ldp     d8,  d9,  [sp], #16
+    ldp     d10, d11, [sp], #16
+    ldp     d12, d13, [sp], #16
+    ldp     d14, d15, [sp], #16
+    ldp     x29, x30, [sp], #16
+    br      lr
+    0:
+    "#
+    : "={x0}" (ret)
+      "={x1}" (ret_sp)
+    : "{x0}" (arg)
+      "{x2}" (new_sp.offset(0))
+    : "x2",  "x3",  "x4",  "x5",  "x6",  "x7",  "x8",  "x9",
+      "x10", "x11", "x12", "x13", "x14", "x15", "x16", "x17",
+      "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26",
+      "x27", "x28", "x30",
+      "v0",  "v1",  "v2",  "v3",  "v4",  "v5",  "v6",  "v7",
+      "v16", "v17", "v18", "v19", "v20", "v21", "v22", "v23",
+      "v24", "v25", "v26", "v27", "v28", "v29", "v30", "v31",
+      "cc", "memory"
+    : "volatile");
+    (ret, mem::transmute(ret_sp))
+}
+
+#[repr(C)]
 #[derive(Debug)]
 pub struct Registers {
-    // We only save the 13 callee-saved registers:
-    //  x19--x28, fp (x29), lr (x30), sp
-    gpr: [usize; 16],
+    sp: usize,
 }
 
 impl Registers {
     pub fn new() -> Registers {
-        Registers { gpr: [0; 16] }
+        Registers { sp: 0 }
+    }
+
+    // use for root thread register init
+    pub fn root() -> Registers {
+        Self::new()
+    }
+
+    #[inline]
+    pub fn get_sp(&self) -> StackPointer {
+        unsafe { StackPointer::new(self.sp as *mut usize) }
     }
 
     #[inline]
+    pub fn set_sp(&mut self, sp: StackPointer) {
+        self.sp = unsafe { mem::transmute(sp) };
+    }
+
+    #[inline(always)]
     pub fn prefetch(&self) {
-        unsafe {
-            prefetch(self as *const _ as *const usize);
-            prefetch(self.gpr[1] as *const usize);
+        if self.sp == 0 {
+            #[cold]
+            return;
         }
+        unsafe { prefetch(self.sp as *const usize) };
     }
-}
 
-pub fn initialize_call_frame(
-    regs: &mut Registers,
-    fptr: InitFn,
-    arg: usize,
-    arg2: *mut usize,
-    stack: &Stack,
-) {
-    // Callee-saved registers start at x19
-    const X19: usize = 19 - 19;
-    const X20: usize = 20 - 19;
-    const X21: usize = 21 - 19;
-    const FP: usize  = 29 - 19;
-    const LR: usize  = 30 - 19;
-    const SP: usize  = 31 - 19;
-    
-    let sp = align_down(stack.end());
-
-    // These registers are frobbed by bootstrap_green_task into the right
-    // location so we can invoke the "real init function", `fptr`.
-    regs.gpr[X19] = arg;
-    regs.gpr[X20] = arg2 as usize;
-    regs.gpr[X21] = fptr as usize;
-
-    // Aarch64 current stack frame pointer
-    regs.gpr[FP] = mut_offset(sp, -4) as usize;
-    
-    regs.gpr[LR] = bootstrap_green_task as usize;
-
-    // setup the init stack
-    // this is prepared for the swap context
-    // leave enough space for stack unwind access
-    regs.gpr[SP] = mut_offset(sp, -4) as usize;
-
-    unsafe {
-        // setup the correct stack frame for unwind
-        *mut_offset(sp, -0) = 0;
-        *mut_offset(sp, -1) = 0;
-        *mut_offset(sp, -2) = 0;
-        *mut_offset(sp, -3) = 0;
+    #[inline]
+    pub unsafe fn restore_context(&mut self) {}
+
+    pub unsafe fn init_with<S: GeneratorStack>(&mut self, fptr: InitFn, stack: &S) {
+        initialize_call_frame(self, fptr, stack);
     }
 }
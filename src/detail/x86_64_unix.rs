@@ -39,17 +39,18 @@
 // * Simulating return is as easy as restoring register values from the CFI table
 //   and then setting stack pointer to CFA.
 //
-// A high-level overview of the function of the trampolines when unwinding is:
-// * The 2nd init trampoline puts a controlled value (written in swap to `new_cfa`)
-//   into %rbp. This is then used as the CFA for the 1st trampoline.
-// * This controlled value points to the bottom of the stack of the parent context,
-//   which holds the saved %rbp and return address from the call to swap().
-// * The 1st init trampoline tells the unwinder to restore %rbp and its return
-//   address from the stack frame at %rbp (in the parent stack), thus continuing
-//   unwinding at the swap call site instead of falling off the end of context stack.
+// A high-level overview of the function of the trampoline when unwinding is:
+// * The trampoline's CFI is just the normal prologue CFI of any function: CFA is
+//   %rbp+16, and the saved %rbp/return address live at CFA-16/CFA-8.
+// * When the trampoline is entered, %rbp holds the address of the CFA slot living
+//   at a fixed offset from the top of the generator's stack. `swap_link` overwrites the
+//   contents of that slot on every resume with the parent context's stack pointer.
+// * This means the unwinder restores %rbp and the return address from the parent
+//   stack's most recent swap_link call frame, continuing unwinding at the swap
+//   call site instead of falling off the end of the context stack.
 use std::mem;
 use reg_context::InitFn;
-use stack::{Stack, StackPointer};
+use stack::{GeneratorStack, StackPointer};
 
 /// prefetch data
 #[inline(always)]
@@ -62,131 +63,89 @@ unsafe fn prefetch(data: *const usize) {
     : "volatile")
 }
 
-unsafe fn initialize_call_frame(regs: &mut Registers, fptr: InitFn, stack: &Stack) {
-    #[naked]
-    unsafe extern "C" fn trampoline_1() {
-        asm!(
-        r#"
-        # gdb has a hardcoded check that rejects backtraces where frame addresses
-        # do not monotonically decrease. It is turned off if the function is called
-        # "__morestack" and that is hardcoded. So, to make gdb backtraces match
-        # the actual unwinder behavior, we call ourselves "__morestack" and mark
-        # the symbol as local; it shouldn't interfere with anything.
-        __morestack:
-        .local __morestack
-
-        # Set up the first part of our DWARF CFI linking stacks together. When
-        # we reach this function from unwinding, %rbp will be pointing at the bottom
-        # of the parent linked stack. This link is set each time swap() is called.
-        # When unwinding the frame corresponding to this function, a DWARF unwinder
-        # will use %rbp+16 as the next call frame address, restore return address
-        # from CFA-8 and restore %rbp from CFA-16. This mirrors what the second half
-        # of `swap_trampoline` does.
-        .cfi_def_cfa %rbp, 16
-        .cfi_offset %rbp, -16
-
-        # This nop is here so that the initial swap doesn't return to the start
-        # of the trampoline, which confuses the unwinder since it will look for
-        # frame information in the previous symbol rather than this one. It is
-        # never actually executed.
-        nop
-
-        # Stack unwinding in some versions of libunwind doesn't seem to like
-        # 1-byte symbols, so we add a second nop here. This instruction isn't
-        # executed either, it is only here to pad the symbol size.
-        nop
-
-        .Lend:
-        .size __morestack, .Lend-__morestack
-        "#
-        : : : : "volatile")
-    }
-
-    #[cfg(target_vendor = "apple")]
-    #[naked]
-    unsafe extern "C" fn trampoline_1() {
-        asm!(
-        r#"
-        # Identical to the above, except avoids .local/.size that aren't available on Mach-O.
-        __morestack:
-        .private_extern __morestack
-        .cfi_def_cfa %rbp, 16
-        .cfi_offset %rbp, -16
-        nop
-        nop
-        "#
-        : : : : "volatile")
-    }
-
-    #[naked]
-    unsafe extern "C" fn trampoline_2() {
-        asm!(
-        r#"
-        # Set up the second part of our DWARF CFI.
-        # When unwinding the frame corresponding to this function, a DWARF unwinder
-        # will restore %rbp (and thus CFA of the first trampoline) from the stack slot.
-        # This stack slot is updated every time swap() is called to point to the bottom
-        # of the stack of the context switch just switched from.
-        .cfi_def_cfa %rbp, 16
-        .cfi_offset %rbp, -16
-
-        # This nop is here so that the return address of the swap trampoline
-        # doesn't point to the start of the symbol. This confuses gdb's backtraces,
-        # causing them to think the parent function is trampoline_1 instead of
-        # trampoline_2.
-        nop
-
-        # Call with the provided function
-        call    *16(%rsp)
-
-        # Restore the stack pointer of the parent context. No CFI adjustments
-        # are needed since we have the same stack frame as trampoline_1.
-        movq    %rsi, %rsp
-
-        # Restore frame pointer of the parent context.
-        popq    %rbp
-        .cfi_adjust_cfa_offset -8
-        .cfi_restore %rbp
+// Entry trampoline planted at the bottom of every generator stack. It is
+// reached exactly once, via the first `swap_link` into a freshly
+// initialized context, and calls `fptr` with a frame that chains, via
+// %rbp, back to whatever context most recently resumed us.
+//
+// Its CFI is just the normal prologue CFI any function would have: %rbp
+// is the frame pointer, and CFA/%rbp itself are restored from it exactly
+// as a DWARF unwinder or a frame-pointer-only tool (perf, dtrace) would
+// expect. There's no separate "first" trampoline and no __morestack
+// trick to satisfy gdb's monotonic-frame check -- we link stacks purely
+// through this one frame-pointer chain plus the CFA slot that
+// `swap_link` refreshes on every resume.
+//
+// Per the standard %rbp+16 CFA rule, CFA-8 is where an unwinder expects to
+// find a *return address* -- a code pointer whose own CFI continues the
+// chain. That word can't be `fptr` itself: `fptr` is an arbitrary function
+// evaluated at instruction offset 0 under its own (unrelated) CFI, which
+// doesn't know anything about our %rbp trick. So CFA-8 holds a pointer
+// back into this same trampoline (skipping the first byte, like the nop
+// trick libfringe itself used). Since the trampoline's own CFI at that
+// point is exactly the rule above, unwinding through it reapplies
+// `%rbp+16` to whatever the CFA slot currently holds -- the live parent
+// frame -- continuing unwinding there instead of misreading `fptr`.
+#[naked]
+unsafe extern "C" fn trampoline() {
+    asm!(
+    r#"
+    .cfi_startproc
+    nop
+    .cfi_def_cfa %rbp, 16
+    .cfi_offset %rbp, -16
+
+    # Call the generator's entry function. Its address was pushed just
+    # above the anchor word that %rsp+8 currently points at.
+    call    *16(%rsp)
+
+    # The generator has finished: restore the parent context's stack
+    # pointer and frame pointer and return into it, just as swap()'s own
+    # tail does for an ordinary yield.
+    movq    %rsi, %rsp
+    popq    %rbp
+    .cfi_adjust_cfa_offset -8
+    .cfi_restore %rbp
 
-        # Clear the stack pointer. We can't call into this context any more once
-        # the function has returned.
-        xorq    %rsi, %rsi
+    # Clear the stack pointer. We can't call into this context any more
+    # once the function has returned.
+    xorq    %rsi, %rsi
 
-        # Return into the parent context. Use `pop` and `jmp` instead of a `ret`
-        # to avoid return address mispredictions (~8ns per `ret` on Ivy Bridge).
-        popq    %rax
-        .cfi_adjust_cfa_offset -8
-        .cfi_register %rip, %rax
-        jmpq    *%rax
-        "#
-        : : : : "volatile")
-    }
+    # Return into the parent context. Use `pop` and `jmp` instead of a
+    # `ret` to avoid return address mispredictions (~8ns per `ret` on
+    # Ivy Bridge).
+    popq    %rax
+    .cfi_adjust_cfa_offset -8
+    .cfi_register %rip, %rax
+    jmpq    *%rax
+    .cfi_endproc
+    "#
+    : : : : "volatile")
+}
 
-    // We set up the stack in a somewhat special way so that to the unwinder it
-    // looks like trampoline_1 has called trampoline_2, which has in turn called
-    // swap::trampoline.
-    //
-    // There are 2 call frames in this setup, each containing the return address
-    // followed by the %rbp value for that frame. This setup supports unwinding
-    // using DWARF CFI as well as the frame pointer-based unwinding used by tools
-    // such as perf or dtrace.
+unsafe fn initialize_call_frame<S: GeneratorStack>(regs: &mut Registers, fptr: InitFn, stack: &S) {
+    // Lay out, from the top of the stack down:
+    //   [padding]        keeps the indirect `call` inside the trampoline
+    //                    16-byte aligned per the SysV ABI
+    //   [fptr]           the function the trampoline should call
+    //   [anchor]         CFA-8: a pointer back into `trampoline` itself,
+    //                    so unwinding through it re-evaluates the same
+    //                    %rbp+16 CFI rule instead of `fptr`'s
+    //   [CFA slot]       CFA-16: refreshed by swap_link() on every resume
+    //   [trampoline]     return address, loaded by the first swap_link
+    //   [&CFA slot]      %rbp, ditto -- a fixed pointer, not a fixed value
     let mut sp = StackPointer::new(stack.end());
 
-    sp.push(0usize); // Padding to ensure the stack is properly aligned
-    sp.push(fptr as usize); // Function that trampoline_2 should call
+    sp.push(0usize);
+    sp.push(fptr as usize);
+    sp.push(trampoline as usize + 1); // anchor: skip the leading nop
 
-    // Call frame for trampoline_2. The CFA slot is updated by swap::trampoline
-    // each time a context switch is performed.
-    sp.push(trampoline_1 as usize + 2); // Return after the 2 nops
-    sp.push(0xdeaddeaddead0cfa); // CFA slot
+    sp.push(0xdeaddeaddead0cfa); // CFA slot, placeholder until the first swap_link
+    let cfa_slot = sp.offset(0) as usize;
 
-    // Call frame for swap::trampoline. We set up the %rbp value to point to the
-    // parent call frame.
-    let frame = sp.offset(0);
-    sp.push(trampoline_2 as usize + 1); // Entry point, skip initial nop
-    sp.push(frame as usize); // Pointer to parent call frame
+    sp.push(trampoline as usize + 1); // entry point, skip the leading nop
+    sp.push(cfa_slot); // %rbp: address of the (refreshed) CFA slot
 
-    // save the sp in register
     regs.sp = sp.offset(0) as usize;
 }
 
@@ -333,7 +292,7 @@ impl Registers {
     #[inline]
     pub unsafe fn restore_context(&mut self) {}
 
-    pub unsafe fn init_with(&mut self, fptr: InitFn, stack: &Stack) {
+    pub unsafe fn init_with<S: GeneratorStack>(&mut self, fptr: InitFn, stack: &S) {
         initialize_call_frame(self, fptr, stack);
     }
 }
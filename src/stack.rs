@@ -0,0 +1,221 @@
+// A `Stack` is a plain heap-allocated block of memory used as the stack
+// for a generator context. It has no guard page and no overflow detection;
+// see `OsStack` for a ready-to-use allocator with guard-page protection.
+
+use std::ptr;
+
+extern crate libc;
+
+#[cfg(feature = "valgrind")]
+extern crate valgrind_request;
+
+/// Anything usable as the backing memory for a generator's stack.
+///
+/// `RegContext::init_with` and the arch-specific `initialize_call_frame`
+/// are generic over this trait rather than hard-coding `Stack`, so an
+/// `OsStack` (or any other implementation) works everywhere a `Stack`
+/// does.
+pub trait GeneratorStack {
+    /// The top of the stack: the address just past the end of the
+    /// allocation, which is where a context switch starts building its
+    /// initial frame.
+    fn end(&self) -> *mut usize;
+}
+
+#[cfg(feature = "valgrind")]
+unsafe fn valgrind_register(start: usize, end: usize) -> usize {
+    valgrind_request::stack_register(start, end)
+}
+
+#[cfg(feature = "valgrind")]
+unsafe fn valgrind_deregister(id: usize) {
+    valgrind_request::stack_deregister(id)
+}
+
+/// A raw stack for a generator context.
+pub struct Stack {
+    buf: Box<[u8]>,
+    #[cfg(feature = "valgrind")]
+    valgrind_id: usize,
+}
+
+impl Stack {
+    /// Allocate a new stack of at least `size` bytes.
+    pub fn new(size: usize) -> Stack {
+        let buf = vec![0u8; size].into_boxed_slice();
+
+        #[cfg(feature = "valgrind")]
+        let valgrind_id = unsafe {
+            let start = buf.as_ptr() as usize;
+            let end = start + buf.len();
+            valgrind_register(start, end)
+        };
+
+        Stack {
+            buf,
+            #[cfg(feature = "valgrind")]
+            valgrind_id,
+        }
+    }
+}
+
+impl GeneratorStack for Stack {
+    fn end(&self) -> *mut usize {
+        unsafe { self.buf.as_ptr().add(self.buf.len()) as *mut usize }
+    }
+}
+
+#[cfg(feature = "valgrind")]
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { valgrind_deregister(self.valgrind_id) };
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+fn round_up_to_page(size: usize, page_size: usize) -> usize {
+    (size + page_size - 1) / page_size * page_size
+}
+
+/// An OS-backed stack allocator that guards against overflow: the lowest
+/// page of the allocation is mapped with no permissions, so a generator
+/// that recurses too deep faults with a clean SIGSEGV at a known address
+/// instead of silently corrupting whatever memory sits below it.
+///
+/// This is the default stack source for generators. `Stack` remains
+/// available directly as a plain heap-allocated stack without a guard
+/// page.
+pub struct OsStack {
+    ptr: *mut u8,
+    len: usize,
+    #[cfg(feature = "valgrind")]
+    valgrind_id: usize,
+}
+
+impl OsStack {
+    /// Allocate a new OS stack of at least `size` bytes, rounded up to a
+    /// whole number of pages past the guard page.
+    pub fn new(size: usize) -> OsStack {
+        let page_size = page_size();
+        let len = round_up_to_page(size, page_size) + page_size;
+
+        unsafe {
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            );
+            if ptr == libc::MAP_FAILED {
+                panic!("failed to mmap a {}-byte stack", len);
+            }
+
+            // Guard page: the lowest page of the mapping.
+            if libc::mprotect(ptr, page_size, libc::PROT_NONE) != 0 {
+                panic!("failed to mprotect the stack guard page");
+            }
+
+            #[cfg(feature = "valgrind")]
+            let valgrind_id = valgrind_register(ptr as usize, ptr as usize + len);
+
+            OsStack {
+                ptr: ptr as *mut u8,
+                len,
+                #[cfg(feature = "valgrind")]
+                valgrind_id,
+            }
+        }
+    }
+}
+
+impl GeneratorStack for OsStack {
+    /// The top of the stack, matching `Stack::end`.
+    fn end(&self) -> *mut usize {
+        unsafe { self.ptr.add(self.len) as *mut usize }
+    }
+}
+
+impl Drop for OsStack {
+    fn drop(&mut self) {
+        #[cfg(feature = "valgrind")]
+        unsafe {
+            valgrind_deregister(self.valgrind_id);
+        }
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// A pointer into a generator's stack, used while building or restoring
+/// its register state during a context switch.
+#[derive(Debug, Copy, Clone)]
+pub struct StackPointer(*mut usize);
+
+impl StackPointer {
+    #[inline]
+    pub unsafe fn new(sp: *mut usize) -> StackPointer {
+        StackPointer(sp)
+    }
+
+    /// Push a word onto the stack, growing it downwards.
+    #[inline]
+    pub fn push(&mut self, value: usize) {
+        unsafe {
+            self.0 = self.0.offset(-1);
+            *self.0 = value;
+        }
+    }
+
+    /// The raw pointer this `StackPointer` currently refers to, offset by
+    /// `count` words.
+    #[inline]
+    pub fn offset(&self, count: isize) -> *mut usize {
+        unsafe { self.0.offset(count) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_os_stack_rounds_up_to_page() {
+        let page_size = page_size();
+
+        // A request smaller than a page still gets a whole guard page plus
+        // a whole page of usable stack.
+        let stk = OsStack::new(1);
+        assert_eq!(stk.len, page_size * 2);
+
+        // A request that already lands on a page boundary doesn't grow an
+        // extra page beyond the guard page.
+        let stk = OsStack::new(page_size);
+        assert_eq!(stk.len, page_size * 2);
+
+        // A request one byte over a page boundary rounds up to the next
+        // whole page, plus the guard page.
+        let stk = OsStack::new(page_size + 1);
+        assert_eq!(stk.len, page_size * 3);
+    }
+
+    #[test]
+    fn test_os_stack_end_matches_allocation() {
+        let stk = OsStack::new(4096);
+        assert_eq!(stk.end(), unsafe { stk.ptr.add(stk.len) as *mut usize });
+    }
+
+    #[test]
+    fn test_os_stack_new_and_drop() {
+        // Just exercise the mmap/mprotect/munmap round-trip; a failure
+        // here panics rather than returning an error.
+        let stk = OsStack::new(2 * 1024 * 1024);
+        assert!(!stk.end().is_null());
+        drop(stk);
+    }
+}